@@ -1,15 +1,21 @@
 use hyper::client::{Request, Response};
 use hyper::header::common::ContentType;
-use hyper::net::{Fresh, Streaming};
+use hyper::net::Fresh;
 use hyper::{HttpResult, HttpIoError};
 
 use mime::{Mime, TopLevel, SubLevel, Attr, Value};
 
 use mime_guess::guess_mime_type;
 
+use rustc_serialize::Encodable;
+use rustc_serialize::json;
+
 use std::io::IoResult;
 use std::io::fs::File;
+use std::io::{MemReader, Reader, Writer};
 use std::io;
+use std::path::Path;
+use std::ascii::AsciiExt;
 
 use super::{MultipartField, MultipartFile};
 
@@ -20,8 +26,6 @@ pub struct Multipart<'a> {
     boundary: String,
 }
 
-/// Shorthand for a writable request (`Request<Streaming>`)
-type ReqWrite = Request<Streaming>;
 
 impl<'a> Multipart<'a> {
 
@@ -41,8 +45,36 @@ impl<'a> Multipart<'a> {
         let filename = file.path().filename_str().map(|s| s.into_string());
         let content_type = guess_mime_type(file.path());
 
-        self.fields.push((name.into_string(), 
-            MultipartField::File(MultipartFile::from_file(filename, file, content_type))));
+        self.add_reader(name, file, filename, content_type);
+    }
+
+    /// Add a file part backed by any `Reader`, with an explicit filename and `Content-Type`.
+    ///
+    /// Use this for data that isn't already sitting in a `File` on disk, e.g. bytes
+    /// read from a socket or generated in memory.
+    pub fn add_reader<R: Reader + 'a>(&mut self, name: &str, reader: R, filename: Option<String>, content_type: Mime) {
+        self.fields.push((name.into_string(),
+            MultipartField::File(MultipartFile::from_file(filename, reader, content_type))));
+    }
+
+    /// Add an in-memory byte buffer as a file part, guessing its `Content-Type` from `filename`.
+    pub fn add_bytes(&mut self, name: &str, filename: &str, bytes: &[u8]) {
+        let content_type = guess_mime_type(&Path::new(filename));
+        self.add_reader(name, MemReader::new(bytes.to_vec()), Some(filename.into_string()), content_type);
+    }
+
+    /// Add a field whose body is `value` serialized as JSON, with a
+    /// `Content-Type: application/json` header instead of a bare text line.
+    pub fn add_json<T: Encodable>(&mut self, name: &str, value: &T) -> Result<(), json::EncoderError> {
+        let json = try!(json::encode(value));
+        self.fields.push((name.into_string(), MultipartField::Json(json)));
+        Ok(())
+    }
+
+    /// Add several files under one field name, wrapped in a nested
+    /// `multipart/mixed` part as described by RFC 2388/7578.
+    pub fn add_file_group(&mut self, name: &str, files: Vec<MultipartFile<'a>>) {
+        self.fields.push((name.into_string(), MultipartField::Files(files)));
     }
 
     /// Apply the appropriate headers to the `Request<Fresh>` and send the data.
@@ -67,17 +99,25 @@ impl<'a> Multipart<'a> {
         headers.set(ContentType(multipart_mime(self.boundary[])))         
     }
 
-    fn write_request(self, req: &mut ReqWrite) -> IoResult<()> {
+    fn write_request<W: Writer>(self, req: &mut W) -> IoResult<()> {
         let Multipart{ fields, boundary } = self;
 
         try!(write_boundary(req, boundary[]));
 
         for (name, field) in fields.into_iter() {
-            try!(write!(req, "Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name));
+            // Only the `name` parameter is common to every field; leave the
+            // header line open so each field writer can append its own
+            // parameters/headers before terminating it.
+            try!(write!(req, "Content-Disposition: form-data; name=\"{}\"", escape_quoted(&*name)));
 
             try!(match field {
-                    MultipartField::Text(text) => write_line(req, &*text),
+                    MultipartField::Text(text) => {
+                        try!(req.write_str("\r\n\r\n"));
+                        write_line(req, &*text)
+                    },
+                    MultipartField::Json(json) => write_json(req, &*json),
                     MultipartField::File(file) => write_file(req, file),
+                    MultipartField::Files(files) => write_file_group(req, files),
                 });
             
             try!(write_boundary(req, boundary[]));     
@@ -88,19 +128,98 @@ impl<'a> Multipart<'a> {
 
 }
 
-fn write_boundary(req: &mut ReqWrite, boundary: &str) -> IoResult<()> {
+fn write_boundary<W: Writer>(req: &mut W, boundary: &str) -> IoResult<()> {
     write!(req, "--{}\r\n", boundary)
 }
 
-fn write_file(req: &mut ReqWrite, mut file: MultipartFile) -> IoResult<()> {
-    try!(file.filename.map(|filename| write!(req, "; filename=\"{}\"\r\n", filename)).unwrap_or(Ok(())));
+fn write_file<W: Writer>(req: &mut W, mut file: MultipartFile) -> IoResult<()> {
+    // Caller has left the Content-Disposition line open (just past
+    // `name="..."`); close it here, adding `; filename="..."` first if present.
+    match file.filename {
+        Some(filename) => try!(write_filename(req, &*filename)),
+        None => try!(req.write_str("\r\n")),
+    }
+
     try!(write!(req, "Content-Type: {}\r\n\r\n", file.content_type));
-    io::util::copy(&mut file.reader, req)         
+    io::util::copy(&mut file.reader, req)
+}
+
+/// Write the `filename` parameter of a `Content-Disposition` header, per RFC 7578.
+///
+/// The plain `filename="..."` value is always escaped against quote/CR/LF
+/// injection; when `filename` isn't pure ASCII we also emit the RFC 5987
+/// `filename*=UTF-8''...` extended form so non-ASCII names survive intact.
+fn write_filename<W: Writer>(req: &mut W, filename: &str) -> IoResult<()> {
+    try!(write!(req, "; filename=\"{}\"", escape_quoted(filename)));
+
+    if !filename.is_ascii() {
+        try!(write!(req, "; filename*=UTF-8''{}", percent_encode_ext_value(filename)));
+    }
+
+    req.write_str("\r\n")
+}
+
+/// Escape `"`, `\`, `\r` and `\n` for use inside an HTTP quoted-string, so that
+/// untrusted field names or filenames can't inject header syntax or CRLFs.
+fn escape_quoted(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Percent-encode `s` per RFC 5987's `ext-value` production, for use in a
+/// `filename*=UTF-8''<...>` parameter.
+fn percent_encode_ext_value(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+
+    for &byte in s.as_bytes().iter() {
+        match byte as char {
+            'A'...'Z' | 'a'...'z' | '0'...'9' | '-' | '.' | '_' | '~' => encoded.push(byte as char),
+            _ => encoded.push_str(format!("%{:02X}", byte).as_slice()),
+        }
+    }
+
+    encoded
 }
 
 /// Specialized write_line that writes CRLF after a line as per W3C specs
-fn write_line(req: &mut ReqWrite, s: &str) -> IoResult<()> {
-    req.write_str(s).and_then(|_| req.write(b"\r\n"))        
+fn write_line<W: Writer>(req: &mut W, s: &str) -> IoResult<()> {
+    req.write_str(s).and_then(|_| req.write(b"\r\n"))
+}
+
+fn write_json<W: Writer>(req: &mut W, json: &str) -> IoResult<()> {
+    // Caller has left the Content-Disposition line open; terminate it before
+    // adding our own header.
+    try!(write!(req, "\r\nContent-Type: application/json\r\n\r\n"));
+    write_line(req, json)
+}
+
+/// Write several files as one part, wrapped in a nested `multipart/mixed`
+/// body with its own (freshly generated) boundary.
+fn write_file_group<W: Writer>(req: &mut W, files: Vec<MultipartFile>) -> IoResult<()> {
+    let inner_boundary = random_alphanumeric(BOUNDARY_LEN);
+
+    // Caller has left the Content-Disposition line open; terminate it before
+    // adding our own header.
+    try!(write!(req, "\r\nContent-Type: multipart/mixed; boundary={}\r\n\r\n", inner_boundary));
+
+    for file in files.into_iter() {
+        try!(write_boundary(req, inner_boundary[]));
+        try!(req.write_str("Content-Disposition: file"));
+        try!(write_file(req, file));
+    }
+
+    write!(req, "--{}--\r\n", inner_boundary)
 }
 
 /// Generate a random alphanumeric sequence of length `len`
@@ -119,7 +238,144 @@ fn multipart_mime(bound: &str) -> Mime {
     Mime(
         TopLevel::Multipart, SubLevel::Ext("form-data".into_string()),
         vec![(Attr::Ext("boundary".into_string()), Value::Ext(bound.into_string()))]
-    )         
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Multipart, MultipartFile};
+    use super::{escape_quoted, percent_encode_ext_value};
+    use super::{Mime, TopLevel, SubLevel};
+
+    use std::io::MemReader;
+
+    fn with_boundary<'a>() -> Multipart<'a> {
+        Multipart { fields: Vec::new(), boundary: "BOUNDARY".into_string() }
+    }
+
+    fn written(multipart: Multipart) -> String {
+        let mut out = Vec::new();
+        multipart.write_request(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Byte offset of the first occurrence of `needle` in `haystack`.
+    fn find(haystack: &str, needle: &str) -> uint {
+        let (h, n) = (haystack.as_bytes(), needle.as_bytes());
+
+        for i in range(0u, h.len() + 1 - n.len()) {
+            if h.slice(i, i + n.len()) == n {
+                return i;
+            }
+        }
+
+        panic!("{} not found in {}", needle, haystack);
+    }
+
+    #[test]
+    fn escape_quoted_handles_quotes_backslashes_and_crlf() {
+        assert_eq!(escape_quoted("plain").as_slice(), "plain");
+        assert_eq!(escape_quoted("a \"quoted\" b").as_slice(), "a \\\"quoted\\\" b");
+        assert_eq!(escape_quoted("back\\slash").as_slice(), "back\\\\slash");
+        assert_eq!(escape_quoted("line\r\nbreak").as_slice(), "line\\r\\nbreak");
+    }
+
+    #[test]
+    fn percent_encode_ext_value_keeps_unreserved_and_encodes_the_rest() {
+        assert_eq!(percent_encode_ext_value("a-Z0_9.~").as_slice(), "a-Z0_9.~");
+        assert_eq!(percent_encode_ext_value("r\u{e9}sum\u{e9}.txt").as_slice(), "r%C3%A9sum%C3%A9.txt");
+    }
+
+    #[test]
+    fn add_text_escapes_a_quoted_name() {
+        let mut m = with_boundary();
+        m.add_text("a \"quoted\" name", "value");
+
+        assert_eq!(written(m).as_slice(), concat!(
+            "--BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"a \\\"quoted\\\" name\"\r\n\r\n",
+            "value\r\n",
+            "--BOUNDARY\r\n"));
+    }
+
+    #[test]
+    fn add_bytes_writes_plain_and_extended_filename_params() {
+        let mut m = with_boundary();
+        m.add_bytes("upload", "r\u{e9}sum\u{e9}.txt", b"hi");
+
+        assert_eq!(written(m).as_slice(), concat!(
+            "--BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"upload\"; filename=\"r\u{e9}sum\u{e9}.txt\"",
+            "; filename*=UTF-8''r%C3%A9sum%C3%A9.txt\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "hi",
+            "--BOUNDARY\r\n"));
+    }
+
+    #[test]
+    fn add_reader_is_equivalent_to_add_bytes_for_ascii_names() {
+        let mut m = with_boundary();
+        m.add_reader("upload", MemReader::new(b"hi".to_vec()), Some("hi.txt".into_string()),
+            Mime(TopLevel::Text, SubLevel::Plain, Vec::new()));
+
+        assert_eq!(written(m).as_slice(), concat!(
+            "--BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"upload\"; filename=\"hi.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "hi",
+            "--BOUNDARY\r\n"));
+    }
+
+    #[test]
+    fn add_json_writes_an_application_json_part() {
+        let mut m = with_boundary();
+        m.add_json("meta", &true).unwrap();
+
+        assert_eq!(written(m).as_slice(), concat!(
+            "--BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"meta\"\r\n",
+            "Content-Type: application/json\r\n\r\n",
+            "true\r\n",
+            "--BOUNDARY\r\n"));
+    }
+
+    #[test]
+    fn add_file_group_nests_a_closed_multipart_mixed_part() {
+        let mut m = with_boundary();
+        m.add_file_group("photos", vec![
+            MultipartFile::from_file(Some("a.txt".into_string()), MemReader::new(b"A".to_vec()),
+                Mime(TopLevel::Text, SubLevel::Plain, Vec::new())),
+            MultipartFile::from_file(Some("b.txt".into_string()), MemReader::new(b"B".to_vec()),
+                Mime(TopLevel::Text, SubLevel::Plain, Vec::new())),
+        ]);
+
+        let out = written(m);
+
+        // The outer part's Content-Type carries the inner (randomly generated)
+        // boundary; pull it out so we can check the inner body is well-formed
+        // and properly closed, without pinning its random value.
+        let marker = "Content-Type: multipart/mixed; boundary=";
+        let start = find(out.as_slice(), marker) + marker.len();
+        let end = start + find(out.slice_from(start), "\r");
+        let inner_boundary = out.slice(start, end).to_string();
+
+        let expected = format!(concat!(
+            "--BOUNDARY\r\n",
+            "Content-Disposition: form-data; name=\"photos\"\r\n",
+            "Content-Type: multipart/mixed; boundary={ib}\r\n\r\n",
+            "--{ib}\r\n",
+            "Content-Disposition: file; filename=\"a.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "A",
+            "--{ib}\r\n",
+            "Content-Disposition: file; filename=\"b.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "B",
+            "--{ib}--\r\n",
+            "--BOUNDARY\r\n"), ib = inner_boundary);
+
+        assert_eq!(out.as_slice(), expected.as_slice());
+    }
 }
 
 
@@ -0,0 +1,52 @@
+//! Building and parsing `multipart/form-data` requests on top of Hyper.
+//!
+//! `client` lets you assemble a request to send; `server` lets you read one
+//! back out again on the receiving end.
+
+#![feature(box_syntax)]
+
+extern crate hyper;
+extern crate mime;
+extern crate mime_guess;
+extern crate rustc_serialize;
+
+#[macro_use]
+extern crate log;
+
+use mime::Mime;
+
+use std::io::Reader;
+
+pub use client::Multipart;
+pub use server::MultipartRequest;
+
+mod client;
+mod server;
+
+/// A single field of a multipart request: plain text, a file, a JSON document,
+/// or a group of files sharing one field name.
+pub enum MultipartField<'a> {
+    Text(String),
+    File(MultipartFile<'a>),
+    Json(String),
+    Files(Vec<MultipartFile<'a>>),
+}
+
+/// A file attached to a multipart request, backed by anything implementing `Reader`.
+pub struct MultipartFile<'a> {
+    filename: Option<String>,
+    content_type: Mime,
+    reader: Box<Reader + 'a>,
+}
+
+impl<'a> MultipartFile<'a> {
+
+    /// Wrap `reader` as a file part with the given `filename` and `content_type`.
+    pub fn from_file<R: Reader + 'a>(filename: Option<String>, reader: R, content_type: Mime) -> MultipartFile<'a> {
+        MultipartFile {
+            filename: filename,
+            content_type: content_type,
+            reader: box reader,
+        }
+    }
+}
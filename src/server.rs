@@ -0,0 +1,499 @@
+use hyper::server::Request;
+use hyper::header::common::ContentType;
+
+use mime::{Mime, TopLevel, SubLevel, Attr, Value};
+
+use std::io::{IoResult, IoError, InvalidInput};
+use std::io::{BufferedReader, MemReader, Reader, Writer};
+use std::io::fs::File;
+use std::io::TempDir;
+
+use super::{Multipart, MultipartField, MultipartFile};
+
+/// File fields larger than this are spilled to a temp file instead of being kept
+/// in memory. Text fields are always read into memory.
+pub const DEFAULT_MAX_MEM_SIZE: uint = 16 * 1024;
+
+impl<'a> Multipart<'a> {
+
+    /// Parse the `boundary` out of `req`'s `Content-Type` header and return an
+    /// iterator over the fields of its `multipart/form-data` body.
+    pub fn from_request(req: Request) -> IoResult<MultipartRequest<Request>> {
+        let boundary = try!(get_boundary(&req));
+        Ok(MultipartRequest::from_reader(req, boundary))
+    }
+}
+
+/// An iterator over the fields of a `multipart/form-data` body.
+pub struct MultipartRequest<R> {
+    reader: BufferedReader<R>,
+    boundary: String,
+    max_mem_size: uint,
+    started: bool,
+    finished: bool,
+}
+
+impl<R: Reader> MultipartRequest<R> {
+
+    /// Wrap `reader`, reading fields delimited by `boundary` out of it.
+    ///
+    /// `boundary` is the bare value of the `boundary` parameter from the
+    /// request's `Content-Type` header, without the leading `--`.
+    pub fn from_reader(reader: R, boundary: String) -> MultipartRequest<R> {
+        MultipartRequest {
+            reader: BufferedReader::new(reader),
+            boundary: boundary,
+            max_mem_size: DEFAULT_MAX_MEM_SIZE,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Files larger than `max_mem_size` bytes are spilled to a temp file
+    /// instead of being buffered in memory. Defaults to `DEFAULT_MAX_MEM_SIZE`.
+    pub fn set_max_mem_size(&mut self, max_mem_size: uint) {
+        self.max_mem_size = max_mem_size;
+    }
+
+    fn read_field(&mut self) -> IoResult<Option<(String, MultipartField<'static>)>> {
+        if !self.started {
+            // Consume any preamble and the opening `--boundary` line.
+            self.started = true;
+            if !try!(self.next_boundary()) {
+                self.finished = true;
+                return Ok(None);
+            }
+        }
+
+        if self.finished {
+            return Ok(None);
+        }
+
+        let (name, filename, content_type) = try!(self.read_headers());
+
+        let field = match filename {
+            Some(filename) => MultipartField::File(try!(self.read_file(filename, content_type))),
+            None => MultipartField::Text(try!(self.read_text())),
+        };
+
+        Ok(Some((name, field)))
+    }
+
+    /// Read up to and including the next `--boundary` line, leaving the reader
+    /// positioned at the start of the following part's headers (or at EOF if
+    /// this was the closing `--boundary--`). Returns `false` on close.
+    fn next_boundary(&mut self) -> IoResult<bool> {
+        loop {
+            let line = try!(self.reader.read_line());
+            let line = line.as_slice().trim_right_chars('\n').trim_right_chars('\r');
+
+            if line == format!("--{}", self.boundary).as_slice() {
+                return Ok(true);
+            }
+
+            if line == format!("--{}--", self.boundary).as_slice() {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Read MIME headers up to the blank line, returning `(name, filename, content_type)`.
+    fn read_headers(&mut self) -> IoResult<(String, Option<String>, Mime)> {
+        let mut name = None;
+        let mut filename = None;
+        let mut content_type = Mime(TopLevel::Text, SubLevel::Plain, Vec::new());
+
+        loop {
+            let line = try!(self.reader.read_line());
+            let line = line.as_slice().trim_right_chars('\n').trim_right_chars('\r');
+
+            if line.is_empty() {
+                break;
+            }
+
+            let mut parts = line.splitn(2, ':');
+            let header = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+
+            if header.eq_ignore_ascii_case("Content-Disposition") {
+                let (name_, filename_) = parse_content_disposition(value);
+                name = name_;
+                filename = filename_;
+            } else if header.eq_ignore_ascii_case("Content-Type") {
+                content_type = try!(value.parse().ok_or_else(||
+                    IoError { kind: InvalidInput, desc: "invalid Content-Type in multipart part", detail: None }
+                ));
+            }
+        }
+
+        let name = try!(name.ok_or_else(||
+            IoError { kind: InvalidInput, desc: "multipart part missing Content-Disposition name", detail: None }
+        ));
+
+        Ok((name, filename, content_type))
+    }
+
+    /// Read the body of a text field up to (but not including) the next boundary.
+    fn read_text(&mut self) -> IoResult<String> {
+        let mut buf = Vec::new();
+        try!(self.read_part_body(&mut buf));
+        Ok(String::from_utf8_lossy(buf.as_slice()).into_owned())
+    }
+
+    /// Read the body of a file field, spilling to a temp file if it exceeds `max_mem_size`.
+    fn read_file(&mut self, filename: String, content_type: Mime) -> IoResult<MultipartFile<'static>> {
+        let max_mem_size = self.max_mem_size;
+
+        match try!(self.read_file_body(max_mem_size)) {
+            FileSink::Mem(buf) =>
+                Ok(MultipartFile::from_file(Some(filename), MemReader::new(buf), content_type)),
+            FileSink::Disk(dir, mut file) => {
+                try!(file.flush());
+                let reopened = try!(File::open(&dir.path().join("upload")));
+                Ok(MultipartFile::from_file(Some(filename), TempFile { file: reopened, _dir: dir }, content_type))
+            },
+        }
+    }
+
+    /// Read a part's body into `out` until (but not including) the next
+    /// `--boundary` delimiter, leaving the reader positioned right after that
+    /// delimiter's line.
+    fn read_part_body<W: Writer>(&mut self, out: &mut W) -> IoResult<()> {
+        // The leading `\r\n` belongs to the delimiter, not the body, and a
+        // boundary can appear as a prefix of the body's own bytes, so we only
+        // recognize it once we've matched the full `\r\n--boundary` sequence.
+        let needle: Vec<u8> = format!("\r\n--{}", self.boundary).into_bytes();
+        let mut window: Vec<u8> = Vec::with_capacity(needle.len());
+
+        loop {
+            let byte = try!(self.reader.read_byte());
+            window.push(byte);
+
+            if window.len() > needle.len() {
+                try!(out.write_u8(window.remove(0)));
+            }
+
+            if window.as_slice() == needle.as_slice() {
+                let closing = try!(self.consume_boundary_tail());
+                self.finished = closing;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Like `read_part_body`, but stops buffering in memory and spills to a
+    /// temp file the moment more than `max_mem_size` bytes have been seen, so
+    /// large uploads aren't held in memory. Reads all the way to (but not
+    /// including) the next `--boundary` delimiter in a single pass, so the
+    /// reader is never left mid-field the way two separate capped/uncapped
+    /// reads would leave it.
+    fn read_file_body(&mut self, max_mem_size: uint) -> IoResult<FileSink> {
+        let needle: Vec<u8> = format!("\r\n--{}", self.boundary).into_bytes();
+        let mut window: Vec<u8> = Vec::with_capacity(needle.len());
+        let mut sink = FileSink::Mem(Vec::new());
+        let mut written = 0u;
+
+        loop {
+            let byte = try!(self.reader.read_byte());
+            window.push(byte);
+
+            if window.len() > needle.len() {
+                try!(sink.write_u8(window.remove(0)));
+                written += 1;
+
+                if written == max_mem_size + 1 {
+                    try!(sink.spill());
+                }
+            }
+
+            if window.as_slice() == needle.as_slice() {
+                let closing = try!(self.consume_boundary_tail());
+                self.finished = closing;
+                return Ok(sink);
+            }
+        }
+    }
+
+    /// After `read_part_body` has matched `\r\n--boundary`, consume the rest
+    /// of that line: either `--\r\n` (closing delimiter) or `\r\n` (more parts
+    /// follow). Returns `true` if this was the closing delimiter.
+    fn consume_boundary_tail(&mut self) -> IoResult<bool> {
+        let rest = try!(self.reader.read_line());
+        Ok(rest.as_slice().trim_right_chars('\n').trim_right_chars('\r') == "--")
+    }
+}
+
+/// Where a file field's body bytes are being accumulated: kept in memory
+/// while small, spilled to a temp file once it exceeds `max_mem_size`.
+enum FileSink {
+    Mem(Vec<u8>),
+    Disk(TempDir, File),
+}
+
+impl FileSink {
+    fn write_u8(&mut self, byte: u8) -> IoResult<()> {
+        match *self {
+            FileSink::Mem(ref mut buf) => { buf.push(byte); Ok(()) },
+            FileSink::Disk(_, ref mut file) => file.write_u8(byte),
+        }
+    }
+
+    /// If still buffered in memory, move what's been read so far into a
+    /// fresh temp file and write there from now on. No-op once already on disk.
+    fn spill(&mut self) -> IoResult<()> {
+        let disk = match *self {
+            FileSink::Mem(ref buf) => {
+                let dir = try!(TempDir::new("multipart"));
+                let mut file = try!(File::create(&dir.path().join("upload")));
+                try!(file.write(buf.as_slice()));
+                Some(FileSink::Disk(dir, file))
+            },
+            FileSink::Disk(..) => None,
+        };
+
+        if let Some(disk) = disk {
+            *self = disk;
+        }
+
+        Ok(())
+    }
+}
+
+/// A file on disk paired with the `TempDir` that owns it, so the directory
+/// isn't removed out from under the file while it's still being read.
+struct TempFile {
+    file: File,
+    _dir: TempDir,
+}
+
+impl Reader for TempFile {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        self.file.read(buf)
+    }
+}
+
+impl<R: Reader> Iterator<IoResult<(String, MultipartField<'static>)>> for MultipartRequest<R> {
+    fn next(&mut self) -> Option<IoResult<(String, MultipartField<'static>)>> {
+        match self.read_field() {
+            Ok(Some(field)) => Some(Ok(field)),
+            Ok(None) => None,
+            Err(e) => { self.finished = true; Some(Err(e)) },
+        }
+    }
+}
+
+/// Pull the `boundary` parameter out of `req`'s `Content-Type` header.
+fn get_boundary(req: &Request) -> IoResult<String> {
+    let mime = match req.headers.get::<ContentType>() {
+        Some(&ContentType(ref mime)) => mime.clone(),
+        None => return Err(IoError { kind: InvalidInput, desc: "request has no Content-Type header", detail: None }),
+    };
+
+    let Mime(top, _, params) = mime;
+
+    if top != TopLevel::Multipart {
+        return Err(IoError { kind: InvalidInput, desc: "request Content-Type is not multipart", detail: None });
+    }
+
+    for (attr, value) in params.into_iter() {
+        if attr == Attr::Ext("boundary".into_string()) {
+            if let Value::Ext(boundary) = value {
+                return Ok(boundary);
+            }
+        }
+    }
+
+    Err(IoError { kind: InvalidInput, desc: "multipart Content-Type missing boundary", detail: None })
+}
+
+/// Parse a `Content-Disposition: form-data; name="..."; filename="..."` line
+/// into its `name` and optional `filename`.
+fn parse_content_disposition(value: &str) -> (Option<String>, Option<String>) {
+    let mut name = None;
+    let mut filename = None;
+
+    for part in split_params(value).into_iter().skip(1) {
+        let part = part.trim();
+        let mut kv = part.splitn(2, '=');
+        let key = kv.next().unwrap_or("").trim();
+        let val = kv.next().unwrap_or("").trim();
+
+        let val = unescape_quoted(val.trim_matches('"'));
+
+        if key == "name" {
+            name = Some(val);
+        } else if key == "filename" {
+            filename = Some(val);
+        }
+    }
+
+    (name, filename)
+}
+
+/// Split a `;`-separated header value into its parameters, without
+/// splitting on a `;` that appears inside a quoted-string value.
+fn split_params(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0u;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, b) in value.bytes().enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match b as char {
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                parts.push(value.slice(start, i));
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+
+    parts.push(value.slice_from(start));
+    parts
+}
+
+/// Reverse the substitutions `escape_quoted` (in `client.rs`) makes when
+/// writing a quoted-string value: `\"` -> `"`, `\\` -> `\`, and the escaped
+/// `\r`/`\n` two-character sequences back to real CR/LF.
+fn unescape_quoted(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    loop {
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('r') => out.push('\r'),
+                Some('n') => out.push('\n'),
+                Some(c) => { out.push('\\'); out.push(c); },
+                None => out.push('\\'),
+            },
+            Some(c) => out.push(c),
+            None => break,
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::MultipartRequest;
+    use super::super::MultipartField;
+
+    use std::io::MemReader;
+
+    fn parse(boundary: &str, body: String) -> MultipartRequest<MemReader> {
+        MultipartRequest::from_reader(MemReader::new(body.into_bytes()), boundary.into_string())
+    }
+
+    #[test]
+    fn parses_text_and_file_fields() {
+        let boundary = "ABCD1234";
+        let body = format!(concat!(
+            "--{b}\r\n",
+            "Content-Disposition: form-data; name=\"title\"\r\n\r\n",
+            "hello world\r\n",
+            "--{b}\r\n",
+            "Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "file contents\r\n",
+            "--{b}--\r\n"), b = boundary);
+
+        let mut fields = parse(boundary, body);
+
+        let (name, field) = fields.next().unwrap().unwrap();
+        assert_eq!(name.as_slice(), "title");
+        match field {
+            MultipartField::Text(text) => assert_eq!(text.as_slice(), "hello world"),
+            _ => panic!("expected a text field"),
+        }
+
+        let (name, field) = fields.next().unwrap().unwrap();
+        assert_eq!(name.as_slice(), "file");
+        match field {
+            MultipartField::File(mut file) => {
+                assert_eq!(file.reader.read_to_end().unwrap().as_slice(), b"file contents");
+            },
+            _ => panic!("expected a file field"),
+        }
+
+        assert!(fields.next().is_none());
+    }
+
+    #[test]
+    fn file_larger_than_max_mem_size_spills_to_disk() {
+        let boundary = "ABCD1234";
+        let contents: String = range(0u, 64).map(|i| (('a' as u8) + (i % 26) as u8) as char).collect();
+
+        let body = format!(concat!(
+            "--{b}\r\n",
+            "Content-Disposition: form-data; name=\"file\"; filename=\"big.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "{contents}\r\n",
+            "--{b}--\r\n"), b = boundary, contents = contents);
+
+        let mut fields = parse(boundary, body);
+        fields.set_max_mem_size(8);
+
+        let (_, field) = fields.next().unwrap().unwrap();
+        match field {
+            MultipartField::File(mut file) => {
+                let read_back = file.reader.read_to_end().unwrap();
+                assert_eq!(String::from_utf8(read_back).unwrap(), contents);
+            },
+            _ => panic!("expected a file field"),
+        }
+
+        assert!(fields.next().is_none());
+    }
+
+    #[test]
+    fn boundary_string_inside_body_is_not_mistaken_for_a_delimiter() {
+        let boundary = "ABCD1234";
+        // No `\r\n` precedes this occurrence of the boundary text, so it must
+        // be treated as ordinary body content, not a delimiter.
+        let text = format!("this has --{} in the middle of it", boundary);
+
+        let body = format!(concat!(
+            "--{b}\r\n",
+            "Content-Disposition: form-data; name=\"text\"\r\n\r\n",
+            "{text}\r\n",
+            "--{b}--\r\n"), b = boundary, text = text);
+
+        let mut fields = parse(boundary, body);
+
+        let (_, field) = fields.next().unwrap().unwrap();
+        match field {
+            MultipartField::Text(got) => assert_eq!(got, text),
+            _ => panic!("expected a text field"),
+        }
+
+        assert!(fields.next().is_none());
+    }
+
+    #[test]
+    fn quoted_name_with_semicolon_and_escapes_round_trips() {
+        let boundary = "ABCD1234";
+        let body = format!(concat!(
+            "--{b}\r\n",
+            "Content-Disposition: form-data; name=\"a; b \\\"quoted\\\" c\"\r\n\r\n",
+            "value\r\n",
+            "--{b}--\r\n"), b = boundary);
+
+        let mut fields = parse(boundary, body);
+
+        let (name, _) = fields.next().unwrap().unwrap();
+        assert_eq!(name.as_slice(), "a; b \"quoted\" c");
+
+        assert!(fields.next().is_none());
+    }
+}